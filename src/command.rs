@@ -1,33 +1,122 @@
 use async_process::unix::CommandExt as _;
 
+enum EnvOp {
+    Set(std::ffi::OsString, std::ffi::OsString),
+    Remove(std::ffi::OsString),
+    Clear,
+}
+
+type PreExecFn = std::sync::Arc<
+    std::sync::Mutex<Box<dyn FnMut() -> std::io::Result<()> + Send>>,
+>;
+
+// Tracks a user-configured `stdin`/`stdout`/`stderr` override across
+// repeated `spawn()` calls. A `std::process::Stdio` cannot be cloned or
+// otherwise reused, so once `Set` has been consumed by a `spawn()` call it
+// becomes `Consumed`: a later `spawn()` call errors out instead of silently
+// falling back to attaching the pty, which would otherwise change the
+// child's I/O wiring between one spawn and the next with no warning.
+enum StdioSlot {
+    Default,
+    Set(std::process::Stdio),
+    Consumed,
+}
+
+impl StdioSlot {
+    fn resolve(
+        &mut self,
+        name: &str,
+        pty: std::process::Stdio,
+    ) -> crate::Result<std::process::Stdio> {
+        match std::mem::replace(self, StdioSlot::Default) {
+            StdioSlot::Default => Ok(pty),
+            StdioSlot::Set(stdio) => {
+                *self = StdioSlot::Consumed;
+                Ok(stdio)
+            }
+            StdioSlot::Consumed => {
+                *self = StdioSlot::Consumed;
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "{name}() was already consumed by a previous \
+                         spawn() call; call {name}() again before \
+                         spawning again"
+                    ),
+                )
+                .into())
+            }
+        }
+    }
+}
+
+/// The output of a finished, pty-attached process, as returned by
+/// [`Command::output`].
+///
+/// Unlike [`std::process::Output`], there is a single combined `output`
+/// field rather than separate `stdout`/`stderr` fields, since a pty merges
+/// both streams of the child onto a single file descriptor and the
+/// separation cannot be recovered after the fact.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Output {
+    /// The exit status of the process.
+    pub status: std::process::ExitStatus,
+
+    /// All data written by the child to the pty, combining what would be
+    /// `stdout` and `stderr` for a non-pty child process.
+    pub output: Vec<u8>,
+}
+
 /// Wrapper around [`async_process::Command`]
+///
+/// Unlike [`async_process::Command`], the configuration stored here is
+/// not consumed by [`spawn`](Self::spawn), apart from any
+/// `stdin`/`stdout`/`stderr` overrides (see [`stdin`](Self::stdin)): a
+/// fresh `async_process::Command` is built from it on every call, so the
+/// same `Command` can be spawned repeatedly (including against different
+/// [`Pty`](crate::Pty) instances) without losing argv, env, or pre-exec
+/// configuration.
 pub struct Command {
-    inner: async_process::Command,
-    stdin: bool,
-    stdout: bool,
-    stderr: bool,
-    pre_exec_set: bool,
-    pre_exec: Option<
-        Box<dyn FnMut() -> std::io::Result<()> + Send + Sync + 'static>,
-    >,
+    program: std::ffi::OsString,
+    args: Vec<std::ffi::OsString>,
+    env: Vec<EnvOp>,
+    current_dir: Option<std::path::PathBuf>,
+    stdin: StdioSlot,
+    stdout: StdioSlot,
+    stderr: StdioSlot,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    groups: Option<Vec<u32>>,
+    arg0: Option<std::ffi::OsString>,
+    pgid: Option<i32>,
+    controlling_terminal: bool,
+    pre_exec: Vec<PreExecFn>,
 }
 
 impl Command {
     /// See [`async_process::Command::new`]
     pub fn new<S: AsRef<std::ffi::OsStr>>(program: S) -> Self {
         Self {
-            inner: async_process::Command::new(program),
-            stdin: false,
-            stdout: false,
-            stderr: false,
-            pre_exec_set: false,
-            pre_exec: None,
+            program: program.as_ref().to_os_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            current_dir: None,
+            stdin: StdioSlot::Default,
+            stdout: StdioSlot::Default,
+            stderr: StdioSlot::Default,
+            uid: None,
+            gid: None,
+            groups: None,
+            arg0: None,
+            pgid: None,
+            controlling_terminal: true,
+            pre_exec: Vec::new(),
         }
     }
 
     /// See [`async_process::Command::arg`]
     pub fn arg<S: AsRef<std::ffi::OsStr>>(&mut self, arg: S) -> &mut Self {
-        self.inner.arg(arg);
+        self.args.push(arg.as_ref().to_os_string());
         self
     }
 
@@ -37,7 +126,8 @@ impl Command {
         I: IntoIterator<Item = S>,
         S: AsRef<std::ffi::OsStr>,
     {
-        self.inner.args(args);
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
         self
     }
 
@@ -47,7 +137,10 @@ impl Command {
         K: AsRef<std::ffi::OsStr>,
         V: AsRef<std::ffi::OsStr>,
     {
-        self.inner.env(key, val);
+        self.env.push(EnvOp::Set(
+            key.as_ref().to_os_string(),
+            val.as_ref().to_os_string(),
+        ));
         self
     }
 
@@ -58,7 +151,12 @@ impl Command {
         K: AsRef<std::ffi::OsStr>,
         V: AsRef<std::ffi::OsStr>,
     {
-        self.inner.envs(vars);
+        for (key, val) in vars {
+            self.env.push(EnvOp::Set(
+                key.as_ref().to_os_string(),
+                val.as_ref().to_os_string(),
+            ));
+        }
         self
     }
 
@@ -67,13 +165,13 @@ impl Command {
         &mut self,
         key: K,
     ) -> &mut Self {
-        self.inner.env_remove(key);
+        self.env.push(EnvOp::Remove(key.as_ref().to_os_string()));
         self
     }
 
     /// See [`async_process::Command::env_clear`]
     pub fn env_clear(&mut self) -> &mut Self {
-        self.inner.env_clear();
+        self.env.push(EnvOp::Clear);
         self
     }
 
@@ -82,37 +180,49 @@ impl Command {
         &mut self,
         dir: P,
     ) -> &mut Self {
-        self.inner.current_dir(dir);
+        self.current_dir = Some(dir.as_ref().to_path_buf());
         self
     }
 
     /// See [`async_process::Command::stdin`]
+    ///
+    /// Note that, because the underlying `Stdio` cannot be reused, this
+    /// override is consumed by the next call to [`spawn`](Self::spawn);
+    /// calling `spawn` again without calling `stdin` again first is an
+    /// error, rather than silently falling back to attaching the pty.
     pub fn stdin<T: Into<std::process::Stdio>>(
         &mut self,
         cfg: T,
     ) -> &mut Self {
-        self.stdin = true;
-        self.inner.stdin(cfg);
+        self.stdin = StdioSlot::Set(cfg.into());
         self
     }
 
     /// See [`async_process::Command::stdout`]
+    ///
+    /// Note that, because the underlying `Stdio` cannot be reused, this
+    /// override is consumed by the next call to [`spawn`](Self::spawn);
+    /// calling `spawn` again without calling `stdout` again first is an
+    /// error, rather than silently falling back to attaching the pty.
     pub fn stdout<T: Into<std::process::Stdio>>(
         &mut self,
         cfg: T,
     ) -> &mut Self {
-        self.stdout = true;
-        self.inner.stdout(cfg);
+        self.stdout = StdioSlot::Set(cfg.into());
         self
     }
 
     /// See [`async_process::Command::stderr`]
+    ///
+    /// Note that, because the underlying `Stdio` cannot be reused, this
+    /// override is consumed by the next call to [`spawn`](Self::spawn);
+    /// calling `spawn` again without calling `stderr` again first is an
+    /// error, rather than silently falling back to attaching the pty.
     pub fn stderr<T: Into<std::process::Stdio>>(
         &mut self,
         cfg: T,
     ) -> &mut Self {
-        self.stderr = true;
-        self.inner.stderr(cfg);
+        self.stderr = StdioSlot::Set(cfg.into());
         self
     }
 
@@ -121,72 +231,256 @@ impl Command {
     /// that child. The pty will be attached to all of `stdin`, `stdout`, and
     /// `stderr` of the child, unless those file descriptors were previously
     /// overridden through calls to [`stdin`](Self::stdin),
-    /// [`stdout`](Self::stdout), or [`stderr`](Self::stderr). The newly
-    /// created child process will also be made the session leader of a new
-    /// session, and will have the given `pty` instance set as its controlling
-    /// terminal.
+    /// [`stdout`](Self::stdout), or [`stderr`](Self::stderr). Unless
+    /// [`controlling_terminal`](Self::controlling_terminal) has been set to
+    /// `false`, the newly created child process will also be made the
+    /// session leader of a new session, and will have the given `pty`
+    /// instance set as its controlling terminal. If
+    /// [`process_group`](Self::process_group) has been called, the child
+    /// will additionally join (or create) the given process group.
+    ///
+    /// All other configuration (argv, env, working directory, uid/gid,
+    /// pre-exec closures, and so on) is retained, so `spawn` may be called
+    /// more than once on the same `Command`, including against different
+    /// `pty` instances (for example, to restart a crashed child).
     ///
     /// # Errors
-    /// Returns an error if we fail to allocate new file descriptors for
-    /// attaching the pty to the child process, or if we fail to spawn the
-    /// child process (see the documentation for
-    /// [`async_process::Command::spawn`]), or if we fail to make the child a
-    /// session leader or set its controlling terminal.
+    /// Returns an error if [`process_group`](Self::process_group) has been
+    /// called without also calling `controlling_terminal(false)` (see
+    /// [`controlling_terminal`](Self::controlling_terminal)), if a custom
+    /// `stdin`/`stdout`/`stderr` override was already consumed by a
+    /// previous call to `spawn` (see [`stdin`](Self::stdin)), if we fail to
+    /// allocate new file descriptors for attaching the pty to the child
+    /// process, or if we fail to spawn the child process (see the
+    /// documentation for [`async_process::Command::spawn`]), or if we fail
+    /// to make the child a session leader, set its controlling terminal, or
+    /// join its process group.
     pub fn spawn(
         &mut self,
         pty: &crate::Pty,
     ) -> crate::Result<async_process::Child> {
+        // setsid() runs after setpgid() and fails if the caller is already
+        // a process group leader (as it is after setpgid(0, 0)); when it
+        // does succeed (after joining another group), it moves the child
+        // into a new process group, undoing the setpgid(): reject the
+        // combination up front rather than failing at fork time.
+        if self.pgid.is_some() && self.controlling_terminal {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "process_group() requires controlling_terminal(false): \
+                 setsid() would either fail or move the child out of the \
+                 requested process group",
+            )
+            .into());
+        }
+
         let pts = pty.pts();
         let (stdin, stdout, stderr) = crate::sys::setup_subprocess(pts)?;
 
-        if !self.stdin {
-            self.inner.stdin(stdin);
+        let mut inner = async_process::Command::new(&self.program);
+        inner.args(&self.args);
+        for op in &self.env {
+            match op {
+                EnvOp::Set(key, val) => {
+                    inner.env(key, val);
+                }
+                EnvOp::Remove(key) => {
+                    inner.env_remove(key);
+                }
+                EnvOp::Clear => {
+                    inner.env_clear();
+                }
+            }
         }
-        if !self.stdout {
-            self.inner.stdout(stdout);
+        if let Some(dir) = &self.current_dir {
+            inner.current_dir(dir);
         }
-        if !self.stderr {
-            self.inner.stderr(stderr);
+        if let Some(arg0) = &self.arg0 {
+            inner.arg0(arg0);
         }
 
+        inner.stdin(self.stdin.resolve("stdin", stdin)?);
+        inner.stdout(self.stdout.resolve("stdout", stdout)?);
+        inner.stderr(self.stderr.resolve("stderr", stderr)?);
+
         let mut session_leader = crate::sys::session_leader(pts);
-        // Safety: setsid() is an async-signal-safe function and ioctl() is a
-        // raw syscall (which is inherently async-signal-safe).
-        if let Some(mut custom) = self.pre_exec.take() {
-            unsafe {
-                self.inner.pre_exec(move || {
+        let groups = self.groups.clone();
+        let gid = self.gid;
+        let uid = self.uid;
+        let pgid = self.pgid;
+        let controlling_terminal = self.controlling_terminal;
+        let custom = self.pre_exec.clone();
+        // Safety: setgroups(), setgid(), setuid(), and setpgid() are raw
+        // syscalls (which are inherently async-signal-safe), and setsid()
+        // is an async-signal-safe function. Closures registered via
+        // pre_exec() carry the same async-signal-safety obligation.
+        //
+        // The order here is explicit and owned by this crate rather than
+        // left to the standard library's fork/exec implementation: groups,
+        // then gid, then uid (each privilege-dropping step must run before
+        // the next, since dropping uid first would leave us unable to
+        // change gid/groups), then process group, then session leader.
+        unsafe {
+            inner.pre_exec(move || {
+                if let Some(groups) = &groups {
+                    crate::sys::set_groups(groups)?;
+                }
+                if let Some(gid) = gid {
+                    crate::sys::set_gid(gid)?;
+                }
+                if let Some(uid) = uid {
+                    crate::sys::set_uid(uid)?;
+                }
+                if let Some(pgid) = pgid {
+                    crate::sys::set_pgid(pgid)?;
+                }
+                if controlling_terminal {
                     session_leader()?;
-                    custom()?;
-                    Ok(())
-                })
-            };
-        } else if !self.pre_exec_set {
-            unsafe { self.inner.pre_exec(session_leader) };
-        }
-        self.pre_exec_set = true;
+                }
+                for f in &custom {
+                    (f.lock().unwrap())()?;
+                }
+                Ok(())
+            })
+        };
+
+        Ok(inner.spawn()?)
+    }
 
-        Ok(self.inner.spawn()?)
+    /// Spawns the command attached to `pty`, concurrently draining the pty
+    /// master into `sink` so that the child cannot deadlock by filling the
+    /// pty's internal buffer, and waits for the child to exit.
+    async fn run<W: futures_lite::io::AsyncWrite + Unpin>(
+        &mut self,
+        pty: &crate::Pty,
+        mut sink: W,
+    ) -> crate::Result<std::process::ExitStatus> {
+        let mut child = self.spawn(pty)?;
+        let mut reader = pty;
+        let (status, copied) = futures_lite::future::zip(
+            child.status(),
+            futures_lite::io::copy(&mut reader, &mut sink),
+        )
+        .await;
+        copied?;
+        Ok(status?)
     }
 
-    /// See [`async_process::unix::CommandExt::uid`]
+    /// Spawns the command attached to `pty`, and waits for it to exit,
+    /// discarding anything it writes to the pty. Like
+    /// [`output`](Self::output), this drives the pty and the child
+    /// concurrently so it cannot deadlock if the child writes more than the
+    /// pty's internal buffer can hold before exiting, but without retaining
+    /// the bytes it writes, since they are discarded as they are read.
+    ///
+    /// # Errors
+    /// Returns an error under the same conditions as [`spawn`](Self::spawn),
+    /// or if reading from the pty or waiting on the child fails.
+    pub async fn status(
+        &mut self,
+        pty: &crate::Pty,
+    ) -> crate::Result<std::process::ExitStatus> {
+        self.run(pty, futures_lite::io::sink()).await
+    }
+
+    /// Spawns the command attached to `pty`, collects everything it writes
+    /// to the pty, and waits for it to exit. Unlike calling
+    /// [`spawn`](Self::spawn) directly, this drives the pty and the child
+    /// concurrently, so it cannot deadlock if the child writes more than the
+    /// pty's internal buffer can hold before exiting.
+    ///
+    /// # Errors
+    /// Returns an error under the same conditions as [`spawn`](Self::spawn),
+    /// or if reading from the pty or waiting on the child fails.
+    pub async fn output(&mut self, pty: &crate::Pty) -> crate::Result<Output> {
+        let mut output = Vec::new();
+        let status = self.run(pty, &mut output).await?;
+        Ok(Output { status, output })
+    }
+
+    /// Sets the process group that the child should join once spawned. A
+    /// `pgid` of `0` causes the child to create a new process group (with
+    /// itself as leader); any other value causes the child to join the
+    /// existing process group with that id. This is useful when spawning
+    /// several commands as stages of a single pipeline, all of which should
+    /// share one process group so that job control (e.g. `^Z`, `fg`, `bg`)
+    /// operates on the pipeline as a whole rather than on individual
+    /// processes.
+    ///
+    /// This is implemented by calling `setpgid(2)` in the pre-exec, so it
+    /// composes with [`pre_exec`](Self::pre_exec).
+    ///
+    /// `setsid(2)` cannot be combined with this: after `setpgid(2)` with a
+    /// `pgid` of `0` the child is a process group leader, for which
+    /// `setsid` always fails, and after joining an existing group `setsid`
+    /// would move the child into a new process group of its own. Because
+    /// of that, this must be
+    /// paired with `controlling_terminal(false)` (see
+    /// [`controlling_terminal`](Self::controlling_terminal)); otherwise
+    /// [`spawn`](Self::spawn) returns an error before forking rather than
+    /// failing the child at fork time.
+    pub fn process_group(&mut self, pgid: i32) -> &mut Self {
+        self.pgid = Some(pgid);
+        self
+    }
+
+    /// Controls whether the spawned child becomes the leader of a new
+    /// session with the `pty` passed to [`spawn`](Self::spawn) set as its
+    /// controlling terminal. Defaults to `true`. Set this to `false` when
+    /// spawning additional stages of a pipeline that should instead join an
+    /// existing session and process group via
+    /// [`process_group`](Self::process_group).
+    pub fn controlling_terminal(&mut self, set: bool) -> &mut Self {
+        self.controlling_terminal = set;
+        self
+    }
+
+    /// Sets the user id to switch to in the child process, analogous to
+    /// [`async_process::unix::CommandExt::uid`]. Applied via an explicit
+    /// `setuid(2)` call in `spawn()`'s pre-exec, after
+    /// [`groups`](Self::groups) and [`gid`](Self::gid) and before the
+    /// process group and session-leader setup, so the ordering is owned by
+    /// this crate rather than relying on the standard library's internal
+    /// fork/exec sequencing.
     pub fn uid(&mut self, id: u32) -> &mut Self {
-        self.inner.uid(id);
+        self.uid = Some(id);
         self
     }
 
-    /// See [`async_process::unix::CommandExt::gid`]
+    /// Sets the group id to switch to in the child process, analogous to
+    /// [`async_process::unix::CommandExt::gid`]. Applied via an explicit
+    /// `setgid(2)` call in `spawn()`'s pre-exec, after
+    /// [`groups`](Self::groups) and before [`uid`](Self::uid); see
+    /// [`uid`](Self::uid) for why this crate owns that ordering explicitly.
     pub fn gid(&mut self, id: u32) -> &mut Self {
-        self.inner.gid(id);
+        self.gid = Some(id);
+        self
+    }
+
+    /// Sets the supplementary group list for the child process, analogous
+    /// to [`async_process::unix::CommandExt::groups`]. Applied via an
+    /// explicit `setgroups(2)` call in `spawn()`'s pre-exec, before
+    /// [`gid`](Self::gid) and [`uid`](Self::uid); see [`uid`](Self::uid)
+    /// for why this crate owns that ordering explicitly.
+    pub fn groups(&mut self, groups: &[u32]) -> &mut Self {
+        self.groups = Some(groups.to_vec());
         self
     }
 
     /// See [`async_process::unix::CommandExt::pre_exec`]
+    ///
+    /// May be called multiple times: each closure is run, in the order
+    /// registered, after the library's own session-leader and process-group
+    /// setup and before `exec`. Since the configured closures are retained
+    /// rather than consumed, they run again on every subsequent call to
+    /// [`spawn`](Self::spawn).
     #[allow(clippy::missing_safety_doc)]
     pub unsafe fn pre_exec<F>(&mut self, f: F) -> &mut Self
     where
         F: FnMut() -> std::io::Result<()> + Send + Sync + 'static,
     {
-        self.pre_exec = Some(Box::new(f));
+        self.pre_exec
+            .push(std::sync::Arc::new(std::sync::Mutex::new(Box::new(f))));
         self
     }
 
@@ -195,7 +489,23 @@ impl Command {
     where
         S: AsRef<std::ffi::OsStr>,
     {
-        self.inner.arg0(arg);
+        self.arg0 = Some(arg.as_ref().to_os_string());
         self
     }
 }
+
+impl crate::Pty {
+    /// Transfers foreground control of this pty's terminal to the process
+    /// group `pgid`, by calling `tcsetpgrp(3)`. `SIGTTOU` and `SIGTTIN` are
+    /// temporarily blocked for the duration of the call, since otherwise the
+    /// calling process would itself be stopped by the kernel if it is not
+    /// already the foreground process group. This is meant to be used
+    /// alongside [`Command::process_group`], to move a pipeline's process
+    /// group in and out of the foreground of a job-control-aware pty.
+    ///
+    /// # Errors
+    /// Returns an error if the underlying `tcsetpgrp` call fails.
+    pub fn set_foreground_pgid(&self, pgid: i32) -> crate::Result<()> {
+        crate::sys::set_foreground_pgid(self.pts(), pgid)
+    }
+}